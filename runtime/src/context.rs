@@ -1,17 +1,74 @@
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::VecDeque;
 
 use crate::*;
 
+/// Number of low bits of a [`PluginIndex`] that store the plugin's slot.
+///
+/// The remaining high bits store a generation counter, bumped every time a slot is
+/// reclaimed. This lets `Context::plugin` and `Context::remove` detect a handle that
+/// outlived its plugin instead of silently aliasing onto whatever now occupies the slot.
+/// Split evenly: 65,536 concurrent slots is already far more than any embedding host
+/// needs, and the other 16 bits give the generation counter enough headroom to survive
+/// realistic long-running reuse churn before it wraps.
+const SLOT_BITS: u32 = 16;
+const SLOT_MASK: i32 = (1 << SLOT_BITS) - 1;
+const SLOT_MAX: i32 = SLOT_MASK;
+const GENERATION_MASK: i32 = (1 << (32 - SLOT_BITS)) - 1;
+
+/// Pack a slot index and generation counter into a single [`PluginIndex`] handle.
+///
+/// `generation` is masked to the bits available above `SLOT_BITS`, so it never bleeds
+/// into the slot bits; a generation past `GENERATION_MASK` wraps around and packs to the
+/// same handle as the smaller generation it wraps onto (`GENERATION_MASK + 1` gives the
+/// handle of generation 0).
+fn pack_index(slot: i32, generation: i32) -> PluginIndex {
+    ((generation & GENERATION_MASK) << SLOT_BITS) | (slot & SLOT_MASK)
+}
+
+/// Split a [`PluginIndex`] handle back into its `(slot, generation)` components.
+fn unpack_index(id: PluginIndex) -> (i32, i32) {
+    (id & SLOT_MASK, ((id as u32) >> SLOT_BITS) as i32)
+}
+
+/// Advance a slot's generation counter, wrapping within the bits available to
+/// [`pack_index`] so a stored generation can never diverge from the packed handle.
+fn bump_generation(generation: i32) -> i32 {
+    (generation + 1) & GENERATION_MASK
+}
+
+/// A single accumulated error, optionally attributed to the plugin that produced it
+pub struct ErrorEntry {
+    /// The plugin this error came from, or `None` for a context-wide error
+    pub plugin: Option<PluginIndex>,
+
+    /// The error message
+    pub message: std::ffi::CString,
+}
+
+/// A single slot in the plugin registry
+#[derive(Default)]
+struct Slot {
+    generation: i32,
+    plugin: Option<Plugin>,
+}
+
 /// A `Context` is used to store and manage plugins
 #[derive(Default)]
 pub struct Context {
-    /// Plugin registry
-    pub plugins: BTreeMap<PluginIndex, Plugin>,
+    /// Plugin registry, indexed by slot
+    ///
+    /// This replaces the previously-`pub` `plugins: BTreeMap<PluginIndex, Plugin>` field;
+    /// call sites that inserted or iterated it directly must migrate to
+    /// `Context::insert_plugin`, `Context::plugin`, and `Context::remove`.
+    slots: Vec<Slot>,
 
-    /// Error message
-    pub error: Option<std::ffi::CString>,
-    next_id: std::sync::atomic::AtomicI32,
-    reclaimed_ids: VecDeque<PluginIndex>,
+    /// Accumulated errors, oldest first
+    ///
+    /// This replaces the previously-`pub` `error: Option<CString>` field; call sites that
+    /// read `context.error` directly must migrate to `Context::set_error`/`set_plugin_error`
+    /// for writes and `Context::last_error`/`errors`/`clear_errors` for reads.
+    error_log: VecDeque<ErrorEntry>,
+    reclaimed_ids: VecDeque<i32>,
 }
 
 const START_REUSING_IDS: usize = 25;
@@ -19,45 +76,65 @@ const START_REUSING_IDS: usize = 25;
 impl Context {
     /// Create a new context
     pub fn new() -> Context {
-        Context {
-            plugins: BTreeMap::new(),
-            error: None,
-            next_id: std::sync::atomic::AtomicI32::new(0),
-            reclaimed_ids: VecDeque::new(),
-        }
+        Context::default()
     }
 
-    /// Get the next valid plugin ID
-    pub fn next_id(&mut self) -> Result<PluginIndex, Error> {
-        // Make sure we haven't exhausted all plugin IDs, it reach this it would require the machine
+    /// Register a plugin with the context and return a handle to it
+    ///
+    /// Growing the registry to make room for a new slot is fallible: if there isn't
+    /// enough memory available this returns `Err` (recorded via `set_error`) instead of
+    /// aborting the process, which matters when hosting plugins we don't control.
+    pub fn insert_plugin(&mut self, plugin: Plugin) -> Result<PluginIndex, Error> {
+        // Make sure we haven't exhausted all plugin slots, it reach this it would require the machine
         // running this code to have a lot of memory - no computer I tested on was able to allocate
         // the max number of plugins.
         //
         // Since `Context::remove` collects IDs that have been removed we will
         // try to use one of those before returning an error
-        let exhausted = self.next_id.load(std::sync::atomic::Ordering::SeqCst) == PluginIndex::MAX;
+        let exhausted = self.slots.len() as i32 > SLOT_MAX;
 
         // If there is a significant number of old IDs we can start to re-use them
         if self.reclaimed_ids.len() >= START_REUSING_IDS || exhausted {
-            if let Some(x) = self.reclaimed_ids.pop_front() {
-                return Ok(x);
+            if let Some(slot) = self.reclaimed_ids.pop_front() {
+                let entry = &mut self.slots[slot as usize];
+                entry.plugin = Some(plugin);
+                return Ok(pack_index(slot, entry.generation));
             }
 
             if exhausted {
-                return Err(anyhow::format_err!(
+                let err = anyhow::format_err!(
                     "All plugin descriptors are in use, unable to allocate a new plugin"
-                ));
+                );
+                self.set_error(&err);
+                return Err(err);
             }
         }
 
-        Ok(self
-            .next_id
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+        if let Err(e) = self.slots.try_reserve(1) {
+            let err = anyhow::format_err!("unable to allocate space for a new plugin: {e}");
+            self.set_error(&err);
+            return Err(err);
+        }
+
+        let slot = self.slots.len() as i32;
+        self.slots.push(Slot {
+            generation: 0,
+            plugin: Some(plugin),
+        });
+        Ok(pack_index(slot, 0))
+    }
+
+    /// Record an error, attributing it to `plugin` or, if `None`, to the context as a whole
+    pub fn set_plugin_error(&mut self, plugin: Option<PluginIndex>, e: impl std::fmt::Debug) {
+        self.error_log.push_back(ErrorEntry {
+            plugin,
+            message: error_string(e),
+        });
     }
 
     /// Set the context error
     pub fn set_error(&mut self, e: impl std::fmt::Debug) {
-        self.error = Some(error_string(e));
+        self.set_plugin_error(None, e);
     }
 
     /// Convenience function to set error and return the value passed as the final parameter
@@ -66,16 +143,195 @@ impl Context {
         x
     }
 
-    /// Get a plugin from the context
+    /// Get the most recently recorded error message, if any
+    ///
+    /// Kept for compatibility with the old single-error API; prefer [`Context::errors`] to
+    /// see every error accumulated during a load/call cycle instead of just the last one.
+    pub fn last_error(&self) -> Option<&std::ffi::CString> {
+        self.error_log.back().map(|entry| &entry.message)
+    }
+
+    /// Iterate over every accumulated error entry, oldest first
+    pub fn errors(&self) -> impl Iterator<Item = &ErrorEntry> {
+        self.error_log.iter()
+    }
+
+    /// Clear the accumulated error history
+    pub fn clear_errors(&mut self) {
+        self.error_log.clear();
+    }
+
+    /// Get a plugin from the context, rejecting a handle whose generation no longer
+    /// matches its slot (i.e. the plugin it referred to has since been removed, whether
+    /// or not the slot has been reused)
     pub fn plugin(&mut self, id: PluginIndex) -> Option<&mut Plugin> {
-        self.plugins.get_mut(&id)
+        let (slot, generation) = unpack_index(id);
+        let entry = self.slots.get_mut(slot as usize)?;
+        if entry.generation != generation {
+            return None;
+        }
+        entry.plugin.as_mut()
     }
 
     /// Remove a plugin from the context
     pub fn remove(&mut self, id: PluginIndex) {
-        self.plugins.remove(&id);
+        let (slot, generation) = unpack_index(id);
+        let entry = match self.slots.get_mut(slot as usize) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if entry.generation != generation {
+            return;
+        }
+
+        entry.plugin = None;
+
+        // Bump the generation so any handle still referring to this slot is rejected
+        entry.generation = bump_generation(entry.generation);
 
         // Collect old IDs in case we need to re-use them
-        self.reclaimed_ids.push_back(id);
+        self.reclaimed_ids.push_back(slot);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The smallest valid WebAssembly module: just the magic number and version, with no
+    /// sections. Enough to exercise `Plugin::new` without needing a real guest module.
+    const EMPTY_WASM: &[u8] = b"\0asm\x01\x00\x00\x00";
+
+    fn new_plugin() -> Plugin {
+        Plugin::new(EMPTY_WASM, [], false).expect("empty wasm module should instantiate")
+    }
+
+    #[test]
+    fn reused_slot_rejects_stale_handle_and_accepts_fresh_one() {
+        let mut ctx = Context::new();
+
+        // First occupant of the slot that will eventually be recycled.
+        let stale = ctx.insert_plugin(new_plugin()).unwrap();
+        assert!(ctx.plugin(stale).is_some());
+        ctx.remove(stale);
+        assert!(
+            ctx.plugin(stale).is_none(),
+            "handle must be rejected as soon as its plugin is removed"
+        );
+
+        // Push enough additional churn for the registry to start reusing reclaimed slots.
+        for _ in 1..START_REUSING_IDS {
+            let id = ctx.insert_plugin(new_plugin()).unwrap();
+            ctx.remove(id);
+        }
+
+        // This insert recycles the original slot with a bumped generation.
+        let fresh = ctx.insert_plugin(new_plugin()).unwrap();
+        let (stale_slot, stale_generation) = unpack_index(stale);
+        let (fresh_slot, fresh_generation) = unpack_index(fresh);
+        assert_eq!(stale_slot, fresh_slot, "the reclaimed slot should be reused");
+        assert_eq!(fresh_generation, bump_generation(stale_generation));
+
+        assert!(ctx.plugin(stale).is_none(), "stale handle must stay rejected");
+        assert!(ctx.plugin(fresh).is_some(), "fresh handle must resolve");
+    }
+
+    #[test]
+    fn errors_accumulate_in_order_and_can_be_cleared() {
+        let mut ctx = Context::new();
+        assert!(ctx.last_error().is_none());
+        assert_eq!(ctx.errors().count(), 0);
+
+        ctx.set_error("first");
+        ctx.set_plugin_error(Some(7), "second");
+
+        let entries: Vec<_> = ctx.errors().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].plugin, None);
+        assert_eq!(entries[0].message.to_string_lossy(), "first");
+        assert_eq!(entries[1].plugin, Some(7));
+        assert_eq!(entries[1].message.to_string_lossy(), "second");
+
+        // The single-message getter is kept for compatibility and reflects the most
+        // recent entry rather than the first one.
+        assert_eq!(ctx.last_error().unwrap().to_string_lossy(), "second");
+
+        ctx.clear_errors();
+        assert!(ctx.last_error().is_none());
+        assert_eq!(ctx.errors().count(), 0);
+    }
+
+    #[test]
+    fn insert_plugin_errors_when_registry_is_exhausted() {
+        // Pre-fill every slot so `insert_plugin` has nowhere left to grow, exercising its
+        // fallible error path instead of aborting.
+        let mut ctx = Context {
+            slots: std::iter::repeat_with(Slot::default)
+                .take(SLOT_MAX as usize + 1)
+                .collect(),
+            error_log: VecDeque::new(),
+            reclaimed_ids: VecDeque::new(),
+        };
+
+        let err = ctx
+            .insert_plugin(new_plugin())
+            .expect_err("a full registry must be rejected rather than growing past SLOT_MAX");
+        assert!(err.to_string().contains("unable to allocate a new plugin"));
+        assert_eq!(
+            ctx.last_error().unwrap().to_string_lossy(),
+            "All plugin descriptors are in use, unable to allocate a new plugin"
+        );
+    }
+
+    #[test]
+    fn insert_plugin_can_fill_the_last_valid_slot() {
+        // The highest slot index the handle can encode (SLOT_MAX) must still be usable;
+        // the registry is only exhausted once every encodable slot is taken.
+        let mut ctx = Context {
+            slots: std::iter::repeat_with(Slot::default)
+                .take(SLOT_MAX as usize)
+                .collect(),
+            error_log: VecDeque::new(),
+            reclaimed_ids: VecDeque::new(),
+        };
+
+        let id = ctx
+            .insert_plugin(new_plugin())
+            .expect("the last encodable slot should still be available");
+        let (slot, _) = unpack_index(id);
+        assert_eq!(slot, SLOT_MAX);
+        assert!(ctx.plugin(id).is_some());
+    }
+
+    #[test]
+    fn pack_unpack_roundtrip() {
+        for slot in [0, 1, 42, SLOT_MASK] {
+            for generation in [0, 1, GENERATION_MASK] {
+                let id = pack_index(slot, generation);
+                assert_eq!(unpack_index(id), (slot, generation));
+            }
+        }
+    }
+
+    #[test]
+    fn pack_index_masks_generation_to_available_bits() {
+        // A generation past what the handle can encode must wrap around to generation 0
+        // without bleeding into the slot bits, while distinct encodable generations on the
+        // same slot still give distinct handles.
+        assert_eq!(unpack_index(pack_index(5, GENERATION_MASK + 1)), (5, 0));
+        assert_ne!(pack_index(5, 0), pack_index(5, 1));
+    }
+
+    #[test]
+    fn generation_survives_many_reuse_cycles() {
+        // Simulate a slot that gets removed and reinserted well past the number of
+        // generations the handle can encode; the stored and packed generation must
+        // never diverge, even across the wraparound.
+        let mut generation = 0;
+        for _ in 0..(GENERATION_MASK as usize + 1) * 3 {
+            let id = pack_index(5, generation);
+            assert_eq!(unpack_index(id), (5, generation));
+            generation = bump_generation(generation);
+        }
+    }
+}